@@ -1,26 +1,31 @@
 use crate::constants::*;
 use crate::object::*;
 use crate::util::*;
+use core::sync::atomic::{AtomicU8, Ordering};
 /// LineMap is used for scanning block for holes
 ///
-/// 根据论文这里实际上应该是一个byte代表一个line，因为使用bit会导致并发安全问题（data race）
-///
-/// TODO 这里原作者使用了bitmap，实际上使用byte就可以直接避免datarace[[1]][[2]]，这里应该使用bytemap
+/// 每个line用一个`AtomicU8`表示，而不是把line mark压进`usize`的bit里。论文也指出
+/// bitmap方案在并发标记时会出问题：多个marker线程对同一个word做read-modify-write
+/// （`*entry |= mask`）会产生data race。一个line独占一个byte之后，各marker线程写入的是
+/// 互不相干的字节，按照C/C++内存模型这就是data-race-free的[[1]][[2]]，于是同一个block
+/// 的并行tracing也随之安全。
 ///
 /// [1]: https://stackoverflow.com/questions/19903338/c-memory-model-and-race-conditions-on-char-arrays
 /// [2]: https://stackoverflow.com/questions/46916696/on-a-64-bit-machine-can-i-safely-operate-on-individual-bytes-of-a-64-bit-quadwo
 pub struct LineMap {
-    bitmap_: [usize; Self::BITMAP_SIZE / core::mem::size_of::<usize>()],
+    bitmap_: [AtomicU8; Self::BITMAP_SIZE],
 }
 impl LineMap {
-    pub fn clear_all(&mut self) {
-        for byte in self.bitmap_.iter_mut() {
-            *byte = 0;
+    pub fn clear_all(&self) {
+        for byte in self.bitmap_.iter() {
+            byte.store(0, Ordering::Relaxed);
         }
     }
     pub fn is_empty(&self) -> bool {
-        for byte in self.bitmap_.iter() {
-            if *byte != 0 {
+        // OR-reduction over the byte map一个word一个word地扫，空block只需要触碰
+        // `BITMAP_SIZE / WORD_BYTES`个word而不是每个line。
+        for w in 0..Self::NUM_WORDS {
+            if self.word_at(w) != 0 {
                 return false;
             }
         }
@@ -34,91 +39,166 @@ impl LineMap {
         visit_end: usize,
         mut visitor: impl FnMut(usize),
     ) {
-        let offset_start = visit_begin - heap_begin;
-        let offset_end = visit_end - heap_begin;
-        let index_start = Self::offset_to_index(offset_start);
-        let index_end = Self::offset_to_index(offset_end);
-        let bit_start = (offset_start / LINE_SIZE) * (core::mem::size_of::<usize>() * 8);
-        let bit_end = (offset_end / LINE_SIZE) * (core::mem::size_of::<usize>() * 8);
-        let mut left_edge = self.bitmap_[index_start];
-        left_edge &= !((1 << bit_start) - 1);
-        let mut right_edge;
-        if index_start < index_end {
-            if left_edge != 0 {
-                let ptr_base = Self::index_to_offset(index_start) as usize + heap_begin;
-                while {
-                    let shift = left_edge.trailing_zeros() as usize;
-                    let obj = ptr_base + shift * LINE_SIZE;
-                    visitor(obj);
-                    left_edge ^= 1 << shift;
-                    left_edge != 0
-                } {}
+        let index_start = Self::offset_to_index(visit_begin - heap_begin);
+        let index_end = Self::offset_to_index(visit_end - heap_begin);
+        for index in index_start..index_end {
+            if self.bitmap_[index].load(Ordering::Relaxed) != 0 {
+                visitor(heap_begin + index * LINE_SIZE);
             }
-            for i in index_start + 1..index_end {
-                let mut w = self.bitmap_[i];
-                if w != 0 {
-                    let ptr_base = Self::index_to_offset(i) as usize + heap_begin;
-                    while {
-                        let shift = w.trailing_zeros() as usize;
-                        let obj = ptr_base + shift * LINE_SIZE;
-                        visitor(obj);
-                        w ^= 1 << shift;
-                        w != 0
-                    } {}
-                }
+        }
+    }
+    /// 一个word里能放多少个line byte。
+    pub const WORD_BYTES: usize = core::mem::size_of::<usize>();
+    /// 一个line一个byte，map的长度就是block里可能的line数，向上取整到整数个word，
+    /// 这样word粒度的扫描永远不会越界（末尾的padding字节恒为0，而扫描都被
+    /// `NUM_LINES_PER_BLOCK`夹住，不会把padding误判成hole）。
+    pub const BITMAP_SIZE: usize = {
+        let lines =
+            (crate::util::round_up(BLOCK_SIZE as u64, LINE_SIZE as u64) / LINE_SIZE as u64) as usize;
+        crate::util::round_up(lines as u64, Self::WORD_BYTES as u64) as usize
+    };
+    /// 多少个整word组成整个map。
+    pub const NUM_WORDS: usize = Self::BITMAP_SIZE / Self::WORD_BYTES;
+    /// 每个byte的最低位拼成的mask（`0x0101..01`），用来把“字节非零”折叠成每个byte一个bit。
+    const BYTE_LOW_BITS: usize = usize::from_ne_bytes([1u8; Self::WORD_BYTES]);
+    pub const fn offset_to_index(offset: usize) -> usize {
+        offset / LINE_SIZE
+    }
+    /// 把第`word_index`个word（`WORD_BYTES`个line byte）读成一个`usize`。
+    ///
+    /// 因为每个byte只会是0或1，这个word本身就是一个“每个byte的最低位=该line被标记”的mask，
+    /// 可以直接用`trailing_zeros`跳到word里第一个被标记的line。
+    ///
+    /// 注意：`LineMap`的对齐是1，整word读取不能假设`bitmap_`落在`usize`边界上，所以这里走
+    /// `read_unaligned`而不是`read`。这个word级读取也**不是**原子的，会和`set`/`clear`写入的
+    /// 单字节撞在一起，因此所有走word粒度的扫描（`is_empty`/`count_free`/`first_free_from`/
+    /// `first_occupied_from`）只能在stop-the-world下调用；并发标记只能走单字节的
+    /// `set`/`clear`/`test`（那些才是per-byte、data-race-free的）。
+    #[inline(always)]
+    fn word_at(&self, word_index: usize) -> usize {
+        debug_assert!(word_index < Self::NUM_WORDS);
+        // SAFETY: `AtomicU8`与`u8`布局相同，`bitmap_`长度是`WORD_BYTES`的整数倍，
+        // `word_index < NUM_WORDS`保证整word读取不越界。`read_unaligned`不要求`usize`对齐；
+        // 非原子语义由调用方的stop-the-world约定兜底（见上）。
+        unsafe {
+            (self.bitmap_.as_ptr() as *const usize)
+                .add(word_index)
+                .read_unaligned()
+        }
+    }
+    /// 从`start`（含）开始，返回第一个被标记（occupied）的line，找不到则返回`end`。
+    ///
+    /// 以word为单位跳：整word为0直接跳过8个line，否则用`trailing_zeros`直达word内
+    /// 第一个非零byte。
+    pub fn first_occupied_from(&self, start: usize, end: usize) -> usize {
+        let mut line = start;
+        while line < end {
+            let word_index = line / Self::WORD_BYTES;
+            let bit = (line % Self::WORD_BYTES) * 8;
+            // 把`line`以下的byte清零，只看当前及之后的line。
+            let w = (self.word_at(word_index) >> bit) << bit;
+            if w != 0 {
+                let hit = word_index * Self::WORD_BYTES + (w.trailing_zeros() as usize / 8);
+                return if hit < end { hit } else { end };
             }
-            if bit_end == 0 {
-                right_edge = 0;
-            } else {
-                right_edge = self.bitmap_[index_end];
+            line = (word_index + 1) * Self::WORD_BYTES;
+        }
+        end
+    }
+    /// 从`start`（含）开始，返回第一个未标记（free）的line，找不到则返回`end`。
+    ///
+    /// 对word取反之后每个byte的最低位就是“该line空闲”，再用`BYTE_LOW_BITS`挑出这些位，
+    /// `trailing_zeros`直达第一个空闲line。
+    pub fn first_free_from(&self, start: usize, end: usize) -> usize {
+        let mut line = start;
+        while line < end {
+            let word_index = line / Self::WORD_BYTES;
+            let bit = (line % Self::WORD_BYTES) * 8;
+            let free = (!self.word_at(word_index)) & Self::BYTE_LOW_BITS;
+            let free = (free >> bit) << bit;
+            if free != 0 {
+                let hit = word_index * Self::WORD_BYTES + (free.trailing_zeros() as usize / 8);
+                return if hit < end { hit } else { end };
             }
-        } else {
-            right_edge = left_edge;
+            line = (word_index + 1) * Self::WORD_BYTES;
+        }
+        end
+    }
+    #[inline(always)]
+    pub fn test(&self, object: usize, heap_begin: usize) -> bool {
+        let index = Self::offset_to_index(object - heap_begin);
+        self.bitmap_[index].load(Ordering::Relaxed) != 0
+    }
+    #[inline(always)]
+    pub fn set(&self, object: usize, heap_begin: usize) -> bool {
+        let index = Self::offset_to_index(object - heap_begin);
+        self.bitmap_[index].swap(1, Ordering::Relaxed) == 0
+    }
+    #[inline(always)]
+    pub fn clear(&self, object: usize, heap_begin: usize) -> bool {
+        let index = Self::offset_to_index(object - heap_begin);
+        self.bitmap_[index].swap(0, Ordering::Relaxed) != 0
+    }
+    /// 统计`0..end`里未标记（free）的line数，按word统计：取反后每个byte的最低位就是
+    /// “该line空闲”，`count_ones`一次数一整个word的空闲line。
+    pub fn count_free(&self, end: usize) -> usize {
+        let mut count = 0;
+        let full_words = end / Self::WORD_BYTES;
+        for w in 0..full_words {
+            let free = (!self.word_at(w)) & Self::BYTE_LOW_BITS;
+            count += free.count_ones() as usize;
         }
-        right_edge &= (1 << bit_end) - 1;
-        if right_edge != 0 {
-            let ptr_base = Self::index_to_offset(index_end) as usize + heap_begin;
-            while {
-                let shift = right_edge.trailing_zeros() as usize;
-                let obj = ptr_base + shift * LINE_SIZE;
-                visitor(obj);
-                right_edge ^= 1 << shift;
-                right_edge != 0
-            } {}
+        for line in full_words * Self::WORD_BYTES..end {
+            if self.bitmap_[line].load(Ordering::Relaxed) == 0 {
+                count += 1;
+            }
         }
+        count
     }
-    pub const BITMAP_SIZE: usize = {
-        let bytes_covered_per_word = LINE_SIZE * (core::mem::size_of::<usize>() * 8);
-        (crate::util::round_up(BLOCK_SIZE as u64, bytes_covered_per_word as _)
-            / bytes_covered_per_word as u64) as usize
-            * core::mem::size_of::<isize>()
-    };
-    pub const fn offset_bit_index(offset: usize) -> usize {
-        (offset / LINE_SIZE) % (core::mem::size_of::<usize>() * 8)
+    #[inline(always)]
+    pub fn new() -> Self {
+        Self {
+            bitmap_: core::array::from_fn(|_| AtomicU8::new(0)),
+        }
     }
-    pub const fn offset_to_index(offset: usize) -> usize {
-        offset / LINE_SIZE / (core::mem::size_of::<usize>() * 8)
+}
+/// ObjectMap记录block里每个object的起始地址，一个16字节granule一个bit。
+///
+/// 保守扫描stack/寄存器时拿到的往往是指向object内部的interior pointer，需要把它还原回
+/// 所属object的header才能标记。这正是解释器（比如miri）在字节数据之外另外维护object
+/// 边界、做offset-to-object查找的那套东西。
+pub struct ObjectMap {
+    bitmap_: [usize; Self::BITMAP_SIZE],
+}
+impl ObjectMap {
+    /// object起始按16字节对齐，一个granule一个bit。
+    pub const GRANULE: usize = 16;
+    const WORD_BITS: usize = core::mem::size_of::<usize>() * 8;
+    pub const BITMAP_SIZE: usize = BLOCK_SIZE / Self::GRANULE / Self::WORD_BITS;
+    const fn granule_index(offset: usize) -> usize {
+        offset / Self::GRANULE
     }
-    pub const fn index_to_offset(index: usize) -> isize {
-        return index as isize * LINE_SIZE as isize * (core::mem::size_of::<usize>() as isize * 8);
+    const fn word_index(offset: usize) -> usize {
+        Self::granule_index(offset) / Self::WORD_BITS
     }
-    pub const fn offset_to_mask(offset: usize) -> usize {
-        1 << ((offset / LINE_SIZE) % (core::mem::size_of::<usize>() * 8))
+    const fn mask(offset: usize) -> usize {
+        1 << (Self::granule_index(offset) % Self::WORD_BITS)
     }
     #[inline(always)]
     pub fn test(&self, object: usize, heap_begin: usize) -> bool {
         let offset = object - heap_begin;
-        let index = Self::offset_to_index(offset as _);
-        let mask = Self::offset_to_mask(offset as _);
-        let entry = self.bitmap_[index as usize];
-        (entry & mask) != 0
+        // `is_in_block`的上界是闭区间，`begin + BLOCK_SIZE`这种one-past-end的保守指针也会过闸，
+        // 而`word_index(BLOCK_SIZE) == BITMAP_SIZE`会越界索引，这里显式夹住。
+        if offset >= BLOCK_SIZE {
+            return false;
+        }
+        (self.bitmap_[Self::word_index(offset)] & Self::mask(offset)) != 0
     }
     #[inline(always)]
     pub fn set(&mut self, object: usize, heap_begin: usize) -> bool {
         let offset = object - heap_begin;
-        let index = Self::offset_to_index(offset as _);
-        let mask = Self::offset_to_mask(offset as _);
-        let entry = &mut self.bitmap_[index as usize];
+        let entry = &mut self.bitmap_[Self::word_index(offset)];
+        let mask = Self::mask(offset);
         if (*entry & mask) == 0 {
             *entry |= mask;
             return true;
@@ -128,20 +208,89 @@ impl LineMap {
     #[inline(always)]
     pub fn clear(&mut self, object: usize, heap_begin: usize) -> bool {
         let offset = object - heap_begin;
-        let index = Self::offset_to_index(offset as _);
-        let mask = Self::offset_to_mask(offset as _);
-        let entry = &mut self.bitmap_[index as usize];
+        let entry = &mut self.bitmap_[Self::word_index(offset)];
+        let mask = Self::mask(offset);
         if (*entry & mask) != 0 {
             *entry &= !mask;
             return true;
         }
         false
     }
+    pub fn clear_all(&mut self) {
+        for word in self.bitmap_.iter_mut() {
+            *word = 0;
+        }
+    }
+    /// 给定block内任意一个interior地址，返回不超过它的最近一个object起始地址。
+    ///
+    /// 先在包含该granule的word里屏蔽掉granule以上的bit，用`leading_zeros`找word内最高的
+    /// 置位bit；该word为空就往低index的word走，直到找到或扫完。
+    pub fn first_object_at_or_before(&self, addr: usize, heap_begin: usize) -> Option<Address> {
+        if addr < heap_begin {
+            return None;
+        }
+        // one-past-end的保守指针（`begin + BLOCK_SIZE`）会让`word_index`越界，夹到最后一个
+        // granule上——“at or before”的语义下它照样从block顶端往回找。
+        let offset = (addr - heap_begin).min(BLOCK_SIZE - 1);
+        let mut word = Self::word_index(offset);
+        let bit = Self::granule_index(offset) % Self::WORD_BITS;
+        let high_mask = if bit == Self::WORD_BITS - 1 {
+            !0
+        } else {
+            (1usize << (bit + 1)) - 1
+        };
+        let mut w = self.bitmap_[word] & high_mask;
+        loop {
+            if w != 0 {
+                let found_bit = Self::WORD_BITS - 1 - w.leading_zeros() as usize;
+                let granule = word * Self::WORD_BITS + found_bit;
+                return Some(Address::from(heap_begin + granule * Self::GRANULE));
+            }
+            if word == 0 {
+                return None;
+            }
+            word -= 1;
+            w = self.bitmap_[word];
+        }
+    }
     #[inline(always)]
     pub fn new() -> Self {
-        let b = [0usize; Self::BITMAP_SIZE / core::mem::size_of::<usize>()];
-        let this = Self { bitmap_: b };
-        this
+        Self {
+            bitmap_: [0usize; Self::BITMAP_SIZE],
+        }
+    }
+}
+/// 填进被回收/未初始化line的sentinel字节。
+#[cfg(feature = "gc-poison")]
+pub const POISON_BYTE: u8 = 0xDE;
+
+/// 调试用的init-mask，一个line一个`AtomicU8`标志（0/1），记录该line当前是否装着一个活着、已初始化的object。
+///
+/// 思路来自解释器给分配物做的uninitialized-byte跟踪：回收一个line时把它填成sentinel并
+/// 翻成“未初始化”，之后任何对这段dead space的读取都能被`assert_initialized`抓到，相当于
+/// 不依赖外部工具就有一个ASAN式的悬垂访问检测。
+#[cfg(feature = "gc-poison")]
+pub struct InitMask {
+    init_: [AtomicU8; NUM_LINES_PER_BLOCK],
+}
+#[cfg(feature = "gc-poison")]
+impl InitMask {
+    pub fn new() -> Self {
+        Self {
+            init_: core::array::from_fn(|_| AtomicU8::new(0)),
+        }
+    }
+    #[inline(always)]
+    pub fn mark_initialized(&self, line: usize) {
+        self.init_[line].store(1, Ordering::Relaxed);
+    }
+    #[inline(always)]
+    pub fn mark_uninitialized(&self, line: usize) {
+        self.init_[line].store(0, Ordering::Relaxed);
+    }
+    #[inline(always)]
+    pub fn is_initialized(&self, line: usize) -> bool {
+        self.init_[line].load(Ordering::Relaxed) != 0
     }
 }
 /// 其实字段只有Block的metadata，数据区域在最后一个字段地址后
@@ -150,16 +299,35 @@ pub struct ImmixBlock {
     /// Bitmap for marking lines
     pub line_map: LineMap,
     /// Bitmap of objects used for conservative marking
-    /// pub object_map: ObjectMap,
+    pub object_map: ObjectMap,
+    /// Per-line initialized/poisoned tracking for debug dangling-access detection
+    #[cfg(feature = "gc-poison")]
+    pub init_mask: InitMask,
     /// Is this block actually allocated
     pub allocated: bool,
     /// How many holes in this block
     pub hole_count: u32,
     pub evacuation_candidate: bool,
+    /// 当前hole的bump游标，从hole高端向低端递减分配。
+    pub cursor: usize,
+    /// 当前hole的下界，`cursor`不能越过它。
+    pub limit: usize,
+    /// 上一次`scan_block`扫到的high offset，耗尽当前hole后从这里继续找下一个hole。
+    pub last_high_offset: u16,
+    /// 这个block是否是可以把物理页还给OS的候选（`reset`后、且`is_empty`为真时）。
+    pub decommit_candidate: bool,
     //pub map: memmap::MmapMut,
 }
 
 impl ImmixBlock {
+    /// block开头被metadata header占掉的line数。`line_map`/`object_map`等字段内联在block
+    /// 起始处，现在已经跨好几条line，所以分配、扫描、poison都必须从这条line之后的数据区
+    /// 开始，不能再把“header只占line 0”当成理所当然（`decommit`用的也是同一个
+    /// `round_up(size_of::<Self>(), ..)`口径）。
+    pub const HEADER_LINES: usize = (crate::util::round_up(
+        core::mem::size_of::<Self>() as u64,
+        LINE_SIZE as u64,
+    ) / LINE_SIZE as u64) as usize;
     /// Get pointer to block from `object` pointer.
     ///
     /// Block地址一定被是BLOCK_SIZE的整数倍，利用此性质进行计算
@@ -170,17 +338,14 @@ impl ImmixBlock {
         let off = object.to_usize() % BLOCK_SIZE;
         (object.to_mut_ptr::<u8>()).offset(-(off as isize)) as *mut ImmixBlock
     }
-    /*pub fn set_gc_object(&mut self, addr: Address) -> bool {
-        unsafe {
-            //let f = addr.to_mut_ptr::<[u64; 2]>().read();
-            let x = self.object_map.set(addr.to_usize(), self.begin());
-            //debug_assert!(addr.to_mut_ptr::<[u64; 2]>().read() == f);
-            x
-        }
+    /// 记录`addr`是一个object的起始地址，分配时调用。
+    pub fn set_gc_object(&mut self, addr: Address) -> bool {
+        self.object_map.set(addr.to_usize(), self.begin())
     }
+    /// 清除`addr`的object起始标记，sweep回收时调用。
     pub fn unset_gc_object(&mut self, addr: Address) -> bool {
         self.object_map.clear(addr.to_usize(), self.begin())
-    }*/
+    }
     /// 输入的at是已经分配好的block实际指针
     pub fn new(at: *mut u8) -> &'static mut Self {
         unsafe {
@@ -188,10 +353,16 @@ impl ImmixBlock {
             debug_assert!(ptr as usize % BLOCK_SIZE == 0);
             ptr.write(Self {
                 line_map: LineMap::new(),
-                //object_map: ObjectMap::new(),
+                object_map: ObjectMap::new(),
+                #[cfg(feature = "gc-poison")]
+                init_mask: InitMask::new(),
                 allocated: false,
                 hole_count: 0,
                 evacuation_candidate: false,
+                cursor: 0,
+                limit: 0,
+                last_high_offset: 0,
+                decommit_candidate: false,
             });
 
             &mut *ptr
@@ -207,14 +378,27 @@ impl ImmixBlock {
             false
         }
     }
-    /*#[inline]
+    #[inline]
     pub fn is_gc_object(&self, p: Address) -> bool {
         if self.is_in_block(p) {
             self.object_map.test(p.to_usize(), self.begin())
         } else {
             false
         }
-    }*/
+    }
+    /// 把block内的一个interior pointer还原成它所属object的起始地址。
+    ///
+    /// 保守扫描拿到的指针可能落在object中间甚至没对齐，用object map找到不超过它的最近
+    /// 一个object起始，从而可以安全地pin/mark只通过interior pointer到达的object。
+    #[inline]
+    pub fn first_object_at_or_before(&self, p: Address) -> Option<Address> {
+        if self.is_in_block(p) {
+            self.object_map
+                .first_object_at_or_before(p.to_usize(), self.begin())
+        } else {
+            None
+        }
+    }
     pub fn begin(&self) -> usize {
         self as *const Self as usize
     }
@@ -237,31 +421,25 @@ impl ImmixBlock {
     /// line是不是很浪费空间？性能优化是不是有限？（待验证）
     pub fn scan_block(&self, last_high_offset: u16) -> Option<(u16, u16)> {
         let last_high_index = last_high_offset as usize / LINE_SIZE;
-        let mut low_index = NUM_LINES_PER_BLOCK - 1;
         /*debug!(
             "Scanning block {:p} for a hole with last_high_offset {}",
             self, last_high_index
         );*/
-        // 保守标记，起始line需要+1
-        for index in (last_high_index + 1)..NUM_LINES_PER_BLOCK {
-            if !self
-                .line_map
-                .test(self.begin() + (index * LINE_SIZE), self.begin())
-            {
-                low_index = index + 1;
-                break;
-            }
-        }
-        let mut high_index = NUM_LINES_PER_BLOCK;
-        for index in low_index..NUM_LINES_PER_BLOCK {
-            if self
-                .line_map
-                .test(self.begin() + (LINE_SIZE * index), self.begin())
-            {
-                high_index = index;
-                break;
-            }
-        }
+        // 保守标记，hole的起始line需要+1。以word为单位跳到第一个空闲line，再跳到下一个
+        // 被占用的line，满block时只触碰若干个word而不是每个line。扫描起点不能低于
+        // `HEADER_LINES`，否则会把hole开在metadata header里。
+        let scan_start = core::cmp::max(last_high_index + 1, Self::HEADER_LINES);
+        let first_free = self
+            .line_map
+            .first_free_from(scan_start, NUM_LINES_PER_BLOCK);
+        let low_index = if first_free < NUM_LINES_PER_BLOCK {
+            first_free + 1
+        } else {
+            NUM_LINES_PER_BLOCK - 1
+        };
+        let high_index = self
+            .line_map
+            .first_occupied_from(low_index, NUM_LINES_PER_BLOCK);
 
         if low_index == high_index && high_index != (NUM_LINES_PER_BLOCK - 1) {
             //debug!("Rescan: Found single line hole? in block {:p}", self);
@@ -287,19 +465,25 @@ impl ImmixBlock {
         None
     }
     pub fn count_holes(&mut self) -> usize {
+        // 一个hole就是一段连续的空闲line。用word级别的跳转在“空闲起点”和“下一个占用line”
+        // 之间来回跳，循环次数是hole数而不是line数。
         let mut holes: usize = 0;
-        let mut in_hole = false;
-        let b = self.begin();
-        for i in 0..NUM_LINES_PER_BLOCK {
-            match (in_hole, self.line_map.test(b + (LINE_SIZE * i), b)) {
-                (false, false) => {
-                    holes += 1;
-                    in_hole = true;
-                }
-                (_, _) => {
-                    in_hole = false;
-                }
+        // 从数据区起点开始：header那几条line恒为未标记，从line 0扫会把header误判成hole并
+        // 把sentinel写进metadata。
+        let mut i = Self::HEADER_LINES;
+        while i < NUM_LINES_PER_BLOCK {
+            let free = self.line_map.first_free_from(i, NUM_LINES_PER_BLOCK);
+            if free >= NUM_LINES_PER_BLOCK {
+                break;
+            }
+            holes += 1;
+            let occ = self.line_map.first_occupied_from(free, NUM_LINES_PER_BLOCK);
+            // 这些被标记翻回hole的line被回收了，poison掉以便之后的访问能被抓到。
+            #[cfg(feature = "gc-poison")]
+            for line in free..occ {
+                self.poison_line(line);
             }
+            i = occ;
         }
         self.hole_count = holes as _;
         holes
@@ -309,21 +493,13 @@ impl ImmixBlock {
     }
 
     pub fn is_empty(&self) -> bool {
-        for i in 0..NUM_LINES_PER_BLOCK {
-            if self
-                .line_map
-                .test(self.begin() + (i * LINE_SIZE), self.begin())
-            {
-                return false;
-            }
-        }
-        true
+        self.line_map.is_empty()
     }
     /// Update the line counter for the given object.
     ///
     /// Increment if `increment`, otherwise do a saturating substraction.
     #[inline(always)]
-    fn modify_line(&mut self, object: Address, mark: bool) {
+    fn modify_line(&self, object: Address, mark: bool) {
         let line_num = Self::object_to_line_num(object);
         let b = self.begin();
 
@@ -337,12 +513,68 @@ impl ImmixBlock {
                 if mark {
                     self.line_map.set(b + (line * LINE_SIZE), b);
                     //debug_assert!(self.line_map.test(b + (line * LINE_SIZE), b));
+                    #[cfg(feature = "gc-poison")]
+                    self.init_mask.mark_initialized(line);
                 } else {
                     self.line_map.clear(b + (line * LINE_SIZE), b);
+                    // 这里**不**poison：保守标记下小object共享一个line，unmark一个object并不
+                    // 意味着整条line死了，填sentinel会踩到同line上还活着的object。真正的回收
+                    // poison放在`count_holes`的sweep里，那里才知道一整条line变回了hole。
                 }
             }
         }
     }
+    /// 把回收掉的line填成sentinel并标记为未初始化。
+    #[cfg(feature = "gc-poison")]
+    fn poison_line(&self, line: usize) {
+        // SAFETY: `line < NUM_LINES_PER_BLOCK`，写的是本block数据区里这条已经死掉的line。
+        unsafe {
+            core::ptr::write_bytes(
+                (self.begin() + line * LINE_SIZE) as *mut u8,
+                POISON_BYTE,
+                LINE_SIZE,
+            );
+        }
+        self.init_mask.mark_uninitialized(line);
+    }
+    /// 在一次load前检查从`addr`所在line起的`size / LINE_SIZE + 1`条line（与`modify_line`
+    /// 相同的保守标记范围）都还装着已初始化的数据；超出这个范围的line不检查。
+    ///
+    /// 读到回收掉的hole或者从没写过的字节都会在这里panic。
+    #[cfg(feature = "gc-poison")]
+    pub fn assert_initialized(&self, addr: Address, size: usize) {
+        let line_num = Self::object_to_line_num(addr);
+        for line in line_num..(line_num + (size / LINE_SIZE) + 1) {
+            assert!(
+                self.init_mask.is_initialized(line),
+                "access to reclaimed or uninitialized line {} in block {:p}",
+                line,
+                self
+            );
+        }
+    }
+    /// 扫一遍block，确认每个未标记line里的每个字节都还是sentinel。
+    ///
+    /// 如果dead space里出现了非sentinel字节，说明有人往已经回收的line里乱写。
+    #[cfg(feature = "gc-poison")]
+    pub fn check_block(&self) {
+        // 只查数据区：header那几条line装的是metadata，不是sentinel，从line 0查会误报。
+        for line in Self::HEADER_LINES..NUM_LINES_PER_BLOCK {
+            if self.line_is_marked(line) {
+                continue;
+            }
+            let base = self.begin() + line * LINE_SIZE;
+            for off in 0..LINE_SIZE {
+                // SAFETY: 读的是本block数据区内未标记line里的字节。
+                let byte = unsafe { (base as *const u8).add(off).read() };
+                assert_eq!(
+                    byte, POISON_BYTE,
+                    "stray write into dead line {} at byte {} in block {:p}",
+                    line, off, self
+                );
+            }
+        }
+    }
     /// Return the number of holes and marked lines in this block.
     ///
     /// A marked line is a line with a count of at least one.
@@ -371,31 +603,111 @@ impl ImmixBlock {
     /// _Note_: You must call count_holes() bevorhand to set the number of
     /// holes.
     pub fn count_holes_and_available_lines(&self) -> (usize, usize) {
-        (self.hole_count as usize, {
-            let mut count = 0;
-            for line in 0..NUM_LINES_PER_BLOCK {
-                if !self
-                    .line_map
-                    .test(line * LINE_SIZE + self.begin(), self.begin())
-                {
-                    count += 1;
-                }
-            }
-            count
-        })
+        (
+            self.hole_count as usize,
+            self.line_map.count_free(NUM_LINES_PER_BLOCK),
+        )
     }
     pub fn reset(&mut self) {
         self.line_map.clear_all();
-        // self.object_map.clear_all();
+        self.object_map.clear_all();
+        // reset把整个数据区还给allocator，所有数据line都变成未初始化的dead space。header
+        // 那几条line装的是刚`clear_all`过的metadata，绝不能被sentinel覆盖。
+        #[cfg(feature = "gc-poison")]
+        for line in Self::HEADER_LINES..NUM_LINES_PER_BLOCK {
+            self.poison_line(line);
+        }
         self.allocated = false;
         self.hole_count = 0;
         self.evacuation_candidate = false;
+        self.cursor = 0;
+        self.limit = 0;
+        self.last_high_offset = 0;
+        // reset之后这个block空了，成为把物理页还给OS的候选。
+        self.decommit_candidate = true;
+    }
+    /// 把这个block数据区的物理页通过`madvise(MADV_DONTNEED)`还给OS，但保留整块映射
+    /// （包括开头的metadata header），这样之后再分配到它时可以直接重新commit，首次触碰
+    /// 时内核补上清零的匿名页。
+    ///
+    /// 只对`is_empty()`为真的block调用。
+    #[cfg(unix)]
+    pub fn decommit(&mut self) {
+        // 页大小是运行期量（不同平台/配置不同），用`sysconf`取而不是引一个编译期常量。
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+        // header那一页要留着（里面就是line_map/object_map等metadata），只还数据区。
+        let header_end = align_usize(core::mem::size_of::<Self>(), page_size);
+        if header_end < BLOCK_SIZE {
+            let start = self.begin() + header_end;
+            let len = BLOCK_SIZE - header_end;
+            unsafe {
+                libc::madvise(start as *mut libc::c_void, len, libc::MADV_DONTNEED);
+            }
+        }
+        self.decommit_candidate = false;
+    }
+    /// 把`scan_block`给出的`(low, high)`hole装进bump游标：`limit`是低端，`cursor`从高端
+    /// 的上一个字节开始向低端递减。
+    fn install_hole(&mut self, low: u16, high: u16) {
+        // `limit`绝不能落进metadata header：new/reset把`last_high_offset`清成0，若hole从
+        // header里开始，向下bump出来的地址和`set_gc_object`写的object map都会踩到block自己的
+        // 元数据。把下界夹到数据区起点。
+        let header_limit = self.begin() + Self::HEADER_LINES * LINE_SIZE;
+        self.limit = core::cmp::max(self.begin() + low as usize, header_limit);
+        self.cursor = self.begin() + high as usize + 1;
+        self.last_high_offset = high;
     }
-    pub fn line_object_mark(&mut self, object: Address) {
+    /// 在block内向下bump分配`size`字节、按`align`（2的幂）对齐的空间。
+    ///
+    /// 从当前hole的高端向低端分配：游标向下对齐到`align`之后，只要`cursor - size >= limit`
+    /// 就分配成功——limit检查只是一次比较，所以high-to-low bump比向上bump更省。当前hole
+    /// 耗尽时调用`scan_block(last_high_offset)`装入下一个hole，block扫完则返回`None`。
+    /// 每次成功分配都会把它占用的line标记上。
+    pub fn bump_alloc(&mut self, size: usize, align: usize) -> Option<Address> {
+        loop {
+            if self.cursor >= size {
+                let candidate = (self.cursor - size) & !(align - 1);
+                if candidate >= self.limit {
+                    self.cursor = candidate;
+                    let addr = Address::from(candidate);
+                    self.mark_alloc_lines(candidate, size);
+                    // 记下object起始，这样chunk0-3重建的object map在这条自包含分配路径上也被
+                    // 填上，保守扫描的`is_gc_object`/`first_object_at_or_before`才能命中。
+                    self.set_gc_object(addr);
+                    return Some(addr);
+                }
+            }
+            // 当前hole放不下了，找下一个hole。
+            match self.scan_block(self.last_high_offset) {
+                Some((low, high)) => self.install_hole(low, high),
+                None => return None,
+            }
+        }
+    }
+    /// 标记一次bump分配占用的所有line。object header此时还没写，所以按已知的`size`直接
+    /// 算出跨越的line，而不是从header读size。
+    #[inline]
+    fn mark_alloc_lines(&self, addr: usize, size: usize) {
+        let b = self.begin();
+        let first = (addr - b) / LINE_SIZE;
+        let last = (addr + size - 1 - b) / LINE_SIZE;
+        for line in first..=last {
+            self.line_map.set(b + line * LINE_SIZE, b);
+            #[cfg(feature = "gc-poison")]
+            self.init_mask.mark_initialized(line);
+        }
+    }
+    /// 标记`object`占用的所有line。
+    ///
+    /// 本方法取`&self`：`LineMap`现在每个line独占一个byte，`set`/`clear`是单字节的原子写，
+    /// 所以多个worker线程可以同时对同一个block里不同的object调用它来并发标记，无需额外的
+    /// 并行入口——并发标记直接复用这个方法即可（唯一的约束是word粒度的扫描不能和标记并发，
+    /// 见[`word_at`](LineMap::word_at)）。
+    pub fn line_object_mark(&self, object: Address) {
         self.modify_line(object, true);
     }
 
-    pub fn line_object_unmark(&mut self, object: Address) {
+    pub fn line_object_unmark(&self, object: Address) {
         self.modify_line(object, false);
     }
     pub fn line_is_marked(&self, line: usize) -> bool {
@@ -407,3 +719,60 @@ impl ImmixBlock {
         (object.to_usize() % BLOCK_SIZE) / LINE_SIZE
     }
 }
+
+/// Block backing子系统：按live/empty比例决定什么时候把空block的物理页还给OS。
+///
+/// `ImmixBlock::new`只是往caller给的裸指针里写metadata，映射一旦建立就一直占着物理内存，
+/// 于是heap收缩时RSS不会跟着降。这里用一对高低水位（类比load factor：live比例在~0.9以上
+/// 就保留继续涨，在~0.35以下就开始释放）来判断内存压力：`release_unused`扫过所有decommit
+/// 候选，对`is_empty()`为真的block调用`decommit`把页还回去，metadata映射留着以便快速
+/// recommit。这样一次collection尖峰之后RSS能回落到和存活数据成比例，而不是一直吃着峰值。
+pub struct BlockBacking {
+    blocks: std::vec::Vec<&'static mut ImmixBlock>,
+}
+impl BlockBacking {
+    /// live比例在这之上就保留现有block（还在涨）。
+    pub const GROW_KEEP_RATIO: f64 = 0.9;
+    /// live比例跌到这之下就开始把空block还给OS。
+    pub const RELEASE_RATIO: f64 = 0.35;
+
+    pub fn new() -> Self {
+        Self {
+            blocks: std::vec::Vec::new(),
+        }
+    }
+    /// 登记一个新backing的block。
+    pub fn add_block(&mut self, block: &'static mut ImmixBlock) {
+        self.blocks.push(block);
+    }
+    /// 当前live（非空）block占总数的比例；没有block时视为满。
+    pub fn live_ratio(&self) -> f64 {
+        if self.blocks.is_empty() {
+            return 1.0;
+        }
+        let live = self.blocks.iter().filter(|b| !b.is_empty()).count();
+        live as f64 / self.blocks.len() as f64
+    }
+    /// live比例是否还在“继续涨”的高水位之上（`GROW_KEEP_RATIO`）；是则应保留现有block、
+    /// 不急着释放，甚至可以再要。
+    pub fn should_grow(&self) -> bool {
+        self.live_ratio() >= Self::GROW_KEEP_RATIO
+    }
+    /// 周期性的释放扫描：只有当live比例跌破低水位`RELEASE_RATIO`（内存压力已经降下来）时
+    /// 才动手，走一遍decommit候选，把`is_empty()`的block的页还给OS。返回实际decommit的
+    /// block数。
+    #[cfg(unix)]
+    pub fn release_unused(&mut self) -> usize {
+        if self.live_ratio() >= Self::RELEASE_RATIO {
+            return 0;
+        }
+        let mut released = 0;
+        for block in self.blocks.iter_mut() {
+            if block.decommit_candidate && block.is_empty() {
+                block.decommit();
+                released += 1;
+            }
+        }
+        released
+    }
+}